@@ -0,0 +1,38 @@
+//! Local listener bind address, either a regular socket address or a Unix domain socket path
+
+use std::{fmt, net::SocketAddr, path::PathBuf};
+
+/// Address that the tunnel's local listener binds to
+#[derive(Debug, Clone)]
+pub enum BindAddr {
+    /// A regular TCP socket address
+    Socket(SocketAddr),
+    /// A Unix domain socket path
+    Unix(PathBuf),
+}
+
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            BindAddr::Socket(ref saddr) => fmt::Display::fmt(saddr, f),
+            BindAddr::Unix(ref path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_socket() {
+        let addr = BindAddr::Socket("127.0.0.1:8080".parse().unwrap());
+        assert_eq!(addr.to_string(), "127.0.0.1:8080");
+    }
+
+    #[test]
+    fn display_unix() {
+        let addr = BindAddr::Unix(PathBuf::from("/tmp/shadowsocks.sock"));
+        assert_eq!(addr.to_string(), "unix:/tmp/shadowsocks.sock");
+    }
+}
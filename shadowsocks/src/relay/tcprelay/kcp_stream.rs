@@ -0,0 +1,132 @@
+//! KCP (ARQ over UDP) transport used as an alternative to a raw `TcpStream` for the uplink
+//! to the shadowsocks server, for use on high-latency or lossy links.
+
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_kcp::{KcpConfig, KcpNoDelayConfig, KcpStream as TokioKcpStream};
+
+/// KCP tuning knobs, mirroring `tokio_kcp::KcpConfig`
+#[derive(Debug, Clone, Copy)]
+pub struct KcpSessionConfig {
+    pub nodelay: bool,
+    pub interval: i32,
+    pub resend: i32,
+    pub nc: bool,
+    pub wnd_size: (u16, u16),
+}
+
+impl KcpSessionConfig {
+    /// The commonly used "turbo" preset: low latency, no congestion control
+    pub fn turbo() -> Self {
+        KcpSessionConfig {
+            nodelay: true,
+            interval: 10,
+            resend: 2,
+            nc: true,
+            wnd_size: (256, 256),
+        }
+    }
+
+    fn to_kcp_config(self) -> KcpConfig {
+        let mut config = KcpConfig::default();
+        config.nodelay = KcpNoDelayConfig {
+            nodelay: self.nodelay,
+            interval: self.interval,
+            resend: self.resend,
+            nc: self.nc,
+        };
+        config.wnd_size = self.wnd_size;
+        config.stream = true;
+        config
+    }
+}
+
+impl Default for KcpSessionConfig {
+    fn default() -> Self {
+        KcpSessionConfig::turbo()
+    }
+}
+
+/// A KCP session wrapped to expose a plain `AsyncRead + AsyncWrite` stream, so it can be
+/// used in place of a `TcpStream` wherever the transport is generic.
+pub struct KcpStream {
+    inner: TokioKcpStream,
+}
+
+impl KcpStream {
+    /// Establish a new KCP session with the remote `addr`
+    pub async fn connect(config: KcpSessionConfig, addr: SocketAddr) -> io::Result<KcpStream> {
+        let inner = TokioKcpStream::connect(&config.to_kcp_config(), addr)
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        Ok(KcpStream { inner })
+    }
+}
+
+impl AsyncRead for KcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for KcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turbo_maps_onto_kcp_config() {
+        let config = KcpSessionConfig::turbo().to_kcp_config();
+        assert_eq!(config.nodelay.nodelay, true);
+        assert_eq!(config.nodelay.interval, 10);
+        assert_eq!(config.nodelay.resend, 2);
+        assert_eq!(config.nodelay.nc, true);
+        assert_eq!(config.wnd_size, (256, 256));
+        assert_eq!(config.stream, true);
+    }
+
+    #[test]
+    fn custom_config_maps_onto_kcp_config() {
+        let session = KcpSessionConfig {
+            nodelay: false,
+            interval: 40,
+            resend: 0,
+            nc: false,
+            wnd_size: (32, 32),
+        };
+        let config = session.to_kcp_config();
+        assert_eq!(config.nodelay.nodelay, false);
+        assert_eq!(config.nodelay.interval, 40);
+        assert_eq!(config.nodelay.resend, 0);
+        assert_eq!(config.nodelay.nc, false);
+        assert_eq!(config.wnd_size, (32, 32));
+        assert_eq!(config.stream, true);
+    }
+}
@@ -0,0 +1,183 @@
+//! PROXY protocol (v1/v2) header encoding
+//!
+//! <https://www.haproxy.org/download/1.8/doc/proxy-protocol.txt>
+
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+};
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// PROXY protocol version to send when relaying a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtoVersion {
+    /// Human-readable single-line header
+    V1,
+    /// Compact binary header
+    V2,
+}
+
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+fn write_v1_header(buf: &mut Vec<u8>, src_addr: SocketAddr, dst_addr: Option<SocketAddr>) {
+    match (src_addr, dst_addr) {
+        (SocketAddr::V4(src), Some(SocketAddr::V4(dst))) => {
+            let _ = write!(
+                buf,
+                "PROXY TCP4 {} {} {} {}\r\n",
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port()
+            );
+        }
+        (SocketAddr::V6(src), Some(SocketAddr::V6(dst))) => {
+            let _ = write!(
+                buf,
+                "PROXY TCP6 {} {} {} {}\r\n",
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port()
+            );
+        }
+        _ => {
+            // Mismatched families, or the destination is indeterminate (e.g. an unresolved
+            // domain name) -- the protocol has no way to express this
+            let _ = write!(buf, "PROXY UNKNOWN\r\n");
+        }
+    }
+}
+
+fn write_v2_header(buf: &mut Vec<u8>, src_addr: SocketAddr, dst_addr: Option<SocketAddr>) {
+    buf.extend_from_slice(&V2_SIGNATURE);
+    // version 2, command PROXY
+    buf.push(0x21);
+
+    match (src_addr, dst_addr) {
+        (SocketAddr::V4(src), Some(SocketAddr::V4(dst))) => {
+            // AF_INET, STREAM
+            buf.push(0x11);
+            buf.extend_from_slice(&12u16.to_be_bytes());
+            buf.extend_from_slice(&src.ip().octets());
+            buf.extend_from_slice(&dst.ip().octets());
+            buf.extend_from_slice(&src.port().to_be_bytes());
+            buf.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        (SocketAddr::V6(src), Some(SocketAddr::V6(dst))) => {
+            // AF_INET6, STREAM
+            buf.push(0x21);
+            buf.extend_from_slice(&36u16.to_be_bytes());
+            buf.extend_from_slice(&src.ip().octets());
+            buf.extend_from_slice(&dst.ip().octets());
+            buf.extend_from_slice(&src.port().to_be_bytes());
+            buf.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        _ => {
+            // Mismatched families, or the destination is indeterminate -- AF_UNSPEC, UNSPEC,
+            // no address block
+            buf.push(0x00);
+            buf.extend_from_slice(&0u16.to_be_bytes());
+        }
+    }
+}
+
+/// Writes a PROXY protocol header describing `src_addr` (the real client) connecting to
+/// `dst_addr` (the forwarded target) into `stream`, before any payload bytes.
+///
+/// `dst_addr` may be `None` if the target couldn't be resolved to a concrete `SocketAddr`
+/// (for example, an unresolved domain name). In that case, and when `src_addr` and `dst_addr`
+/// are of different address families, the header carries no addresses at all, the source
+/// included: v1 sends `PROXY UNKNOWN`, v2 sends `AF_UNSPEC` with an empty address block.
+pub async fn send_proxy_protocol_header<S>(
+    stream: &mut S,
+    version: ProxyProtoVersion,
+    src_addr: SocketAddr,
+    dst_addr: Option<SocketAddr>,
+) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut buf = Vec::with_capacity(64);
+    match version {
+        ProxyProtoVersion::V1 => write_v1_header(&mut buf, src_addr, dst_addr),
+        ProxyProtoVersion::V2 => write_v2_header(&mut buf, src_addr, dst_addr),
+    }
+
+    stream.write_all(&buf).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn v6(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let mut buf = Vec::new();
+        write_v1_header(&mut buf, v4("127.0.0.1:1234"), Some(v4("192.168.0.1:443")));
+        assert_eq!(buf, b"PROXY TCP4 127.0.0.1 192.168.0.1 1234 443\r\n");
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let mut buf = Vec::new();
+        write_v1_header(&mut buf, v6("[::1]:1234"), Some(v6("[::2]:443")));
+        assert_eq!(buf, b"PROXY TCP6 ::1 ::2 1234 443\r\n");
+    }
+
+    #[test]
+    fn v1_unknown_when_destination_missing() {
+        let mut buf = Vec::new();
+        write_v1_header(&mut buf, v4("127.0.0.1:1234"), None);
+        assert_eq!(buf, b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn v1_unknown_when_families_mismatch() {
+        let mut buf = Vec::new();
+        write_v1_header(&mut buf, v4("127.0.0.1:1234"), Some(v6("[::2]:443")));
+        assert_eq!(buf, b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn v2_tcp4() {
+        let mut buf = Vec::new();
+        write_v2_header(&mut buf, v4("127.0.0.1:1234"), Some(v4("192.168.0.1:443")));
+        assert_eq!(&buf[..12], &V2_SIGNATURE);
+        assert_eq!(buf[12], 0x21);
+        assert_eq!(buf[13], 0x11);
+        assert_eq!(&buf[14..16], &12u16.to_be_bytes());
+        assert_eq!(&buf[16..20], &[127, 0, 0, 1]);
+        assert_eq!(&buf[20..24], &[192, 168, 0, 1]);
+        assert_eq!(&buf[24..26], &1234u16.to_be_bytes());
+        assert_eq!(&buf[26..28], &443u16.to_be_bytes());
+        assert_eq!(buf.len(), 28);
+    }
+
+    #[test]
+    fn v2_tcp6() {
+        let mut buf = Vec::new();
+        write_v2_header(&mut buf, v6("[::1]:1234"), Some(v6("[::2]:443")));
+        assert_eq!(buf[13], 0x21);
+        assert_eq!(&buf[14..16], &36u16.to_be_bytes());
+        assert_eq!(buf.len(), 16 + 36);
+    }
+
+    #[test]
+    fn v2_unspec_when_destination_missing() {
+        let mut buf = Vec::new();
+        write_v2_header(&mut buf, v4("127.0.0.1:1234"), None);
+        assert_eq!(&buf[12..], &[0x21, 0x00, 0x00, 0x00]);
+    }
+}
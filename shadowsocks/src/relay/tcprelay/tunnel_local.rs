@@ -1,15 +1,19 @@
 //! Local server that establish a TCP tunnel with server
 
 use std::{
+    fmt,
     io::{self, ErrorKind},
     net::SocketAddr,
     time::Duration,
 };
 
 use futures::future::{self, Either};
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use tokio::{
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UnixListener},
+    sync::watch,
+    task::JoinSet,
     time,
 };
 
@@ -21,24 +25,159 @@ use crate::{
     },
 };
 
-use super::ProxyStream;
+use super::{
+    bind_addr::BindAddr,
+    kcp_stream::{KcpSessionConfig, KcpStream},
+    outbound_proxy::OutboundProxy,
+    proxy_protocol::{send_proxy_protocol_header, ProxyProtoVersion},
+    ProxyStream,
+};
+
+/// The accepted client's address, for logging and (when available) the PROXY protocol header
+///
+/// A Unix domain socket client has no meaningful `SocketAddr`, so it is represented distinctly
+/// rather than papered over with a fake one -- a synthetic address fed into the PROXY protocol
+/// header would misattribute every connection to the same bogus source.
+#[derive(Debug, Clone, Copy)]
+enum ClientAddr {
+    Socket(SocketAddr),
+    Unix,
+}
+
+impl ClientAddr {
+    fn as_socket_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            ClientAddr::Socket(addr) => Some(addr),
+            ClientAddr::Unix => None,
+        }
+    }
+}
+
+impl fmt::Display for ClientAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ClientAddr::Socket(ref addr) => fmt::Display::fmt(addr, f),
+            ClientAddr::Unix => write!(f, "<unix socket client>"),
+        }
+    }
+}
+
+/// Transport carrying the uplink connection to the shadowsocks server
+#[derive(Debug, Clone, Copy)]
+pub enum TunnelTransport {
+    /// Plain TCP, the default
+    Tcp,
+    /// KCP (ARQ over UDP), tuned with the given session config
+    Kcp(KcpSessionConfig),
+}
+
+impl Default for TunnelTransport {
+    fn default() -> Self {
+        TunnelTransport::Tcp
+    }
+}
+
+/// Marker trait so a TCP-backed and a KCP-backed `ProxyStream` can be held behind one
+/// trait object and driven by `copy_p2s`/`copy_s2p` generically
+trait UplinkIo: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> UplinkIo for T {}
+
+async fn connect_uplink(
+    server: &SharedPlainServerStatistic,
+    addr: &Address,
+    transport: TunnelTransport,
+) -> io::Result<Box<dyn UplinkIo>> {
+    let svr_cfg = server.server_config();
+
+    match transport {
+        TunnelTransport::Tcp => match server.config().outbound_proxy {
+            Some(ref outbound_proxy) => {
+                let svr_addr = server
+                    .clone_context()
+                    .resolve_remote_addr(svr_cfg.addr())
+                    .await?;
+                let chained = outbound_proxy.connect(svr_addr).await?;
+                let svr_s =
+                    ProxyStream::from_stream(server.clone_context(), svr_cfg, addr, chained)
+                        .await?;
+                Ok(Box::new(svr_s))
+            }
+            None => {
+                let svr_s =
+                    ProxyStream::connect_proxied(server.clone_context(), svr_cfg, addr).await?;
+                Ok(Box::new(svr_s))
+            }
+        },
+        TunnelTransport::Kcp(kcp_config) => {
+            // HTTP/SOCKS5 CONNECT proxying only ever tunnels a TCP byte stream, so it cannot
+            // carry a KCP (ARQ-over-UDP) session. Reject the combination outright instead of
+            // silently dialing the shadowsocks server directly and dropping `outbound_proxy`.
+            if server.config().outbound_proxy.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "`outbound_proxy` is not supported with the KCP transport",
+                ));
+            }
+
+            let svr_addr = server
+                .clone_context()
+                .resolve_remote_addr(svr_cfg.addr())
+                .await?;
+            let kcp = KcpStream::connect(kcp_config, svr_addr).await?;
+            let svr_s =
+                ProxyStream::from_stream(server.clone_context(), svr_cfg, addr, kcp).await?;
+            Ok(Box::new(svr_s))
+        }
+    }
+}
 
 /// Established Client Tunnel
 ///
 /// This method must be called after handshaking with client (for example, socks5 handshaking)
-async fn establish_client_tcp_tunnel<'a>(
+async fn establish_client_tcp_tunnel<S>(
     server: &SharedPlainServerStatistic,
-    mut s: TcpStream,
-    client_addr: SocketAddr,
+    mut s: S,
+    client_addr: ClientAddr,
     addr: &Address,
-) -> io::Result<()> {
+    send_proxy_protocol: Option<ProxyProtoVersion>,
+    transport: TunnelTransport,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     let svr_cfg = server.server_config();
 
     // NOTE: TUNNEL doesn't need to check ACL, just forward everything to proxy server
-    let svr_s = ProxyStream::connect_proxied(server.clone_context(), svr_cfg, addr).await?;
-    let (mut svr_r, mut svr_w) = svr_s.split();
+    let mut svr_s = connect_uplink(server, addr, transport).await?;
 
-    let (mut r, mut w) = s.split();
+    if let Some(version) = send_proxy_protocol {
+        match client_addr.as_socket_addr() {
+            Some(src_addr) => {
+                let dst_addr = match *addr {
+                    Address::SocketAddress(sa) => Some(sa),
+                    Address::DomainNameAddress(..) => None,
+                };
+                send_proxy_protocol_header(&mut svr_s, version, src_addr, dst_addr).await?;
+                trace!(
+                    "TUNNEL sent PROXY protocol {:?} header for {}",
+                    version,
+                    client_addr
+                );
+            }
+            None => {
+                // There is no real client address to report for a Unix domain socket client,
+                // and sending a fake one would be worse than not sending a header at all
+                warn!(
+                    "TUNNEL cannot send PROXY protocol {:?} header for {}, no real client address is available",
+                    version, client_addr
+                );
+            }
+        }
+    }
+
+    let (mut svr_r, mut svr_w) = tokio::io::split(svr_s);
+
+    let (mut r, mut w) = tokio::io::split(s);
 
     use super::utils::{copy_p2s, copy_s2p};
 
@@ -54,17 +193,33 @@ async fn establish_client_tcp_tunnel<'a>(
         Either::Left((Ok(..), _)) => trace!("TUNNEL relay {} -> {} closed", client_addr, addr),
         Either::Left((Err(err), _)) => {
             if let ErrorKind::TimedOut = err.kind() {
-                trace!("TUNNEL relay {} -> {} closed with error {}", client_addr, addr, err);
+                trace!(
+                    "TUNNEL relay {} -> {} closed with error {}",
+                    client_addr,
+                    addr,
+                    err
+                );
             } else {
-                debug!("TUNNEL relay {} -> {} closed with error {}", client_addr, addr, err);
+                debug!(
+                    "TUNNEL relay {} -> {} closed with error {}",
+                    client_addr, addr, err
+                );
             }
         }
         Either::Right((Ok(..), _)) => trace!("TUNNEL relay {} <- {} closed", client_addr, addr),
         Either::Right((Err(err), _)) => {
             if let ErrorKind::TimedOut = err.kind() {
-                trace!("TUNNEL relay {} <- {} closed with error {}", client_addr, addr, err);
+                trace!(
+                    "TUNNEL relay {} <- {} closed with error {}",
+                    client_addr,
+                    addr,
+                    err
+                );
             } else {
-                debug!("TUNNEL relay {} <- {} closed with error {}", client_addr, addr, err);
+                debug!(
+                    "TUNNEL relay {} <- {} closed with error {}",
+                    client_addr, addr, err
+                );
             }
         }
     }
@@ -74,7 +229,35 @@ async fn establish_client_tcp_tunnel<'a>(
     Ok(())
 }
 
-async fn handle_tunnel_client(server: &SharedPlainServerStatistic, s: TcpStream) -> io::Result<()> {
+async fn handle_tunnel_client<S>(
+    server: &SharedPlainServerStatistic,
+    s: S,
+    client_addr: ClientAddr,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // forward must not be None, it is already checked in local.rs
+    let target_addr = server.config().forward.as_ref().unwrap();
+
+    let send_proxy_protocol = server.config().tunnel_send_proxy_protocol;
+    let transport = server.config().tunnel_transport;
+
+    establish_client_tcp_tunnel(
+        server,
+        s,
+        client_addr,
+        target_addr,
+        send_proxy_protocol,
+        transport,
+    )
+    .await
+}
+
+async fn handle_tunnel_tcp_client(
+    server: &SharedPlainServerStatistic,
+    s: TcpStream,
+) -> io::Result<()> {
     // let svr_cfg = server.server_config();
     //
     // FIXME: set_keepalive have been removed from tokio 0.3
@@ -85,49 +268,62 @@ async fn handle_tunnel_client(server: &SharedPlainServerStatistic, s: TcpStream)
 
     if server.config().no_delay {
         if let Err(err) = s.set_nodelay(true) {
-            error!("failed to set TCP_NODELAY on accepted socket, error: {:?}", err);
+            error!(
+                "failed to set TCP_NODELAY on accepted socket, error: {:?}",
+                err
+            );
         }
     }
 
-    let client_addr = s.peer_addr()?;
+    let client_addr = ClientAddr::Socket(s.peer_addr()?);
 
-    // forward must not be None, it is already checked in local.rs
-    let target_addr = server.config().forward.as_ref().unwrap();
-
-    establish_client_tcp_tunnel(server, s, client_addr, target_addr).await
+    handle_tunnel_client(server, s, client_addr).await
 }
 
-pub async fn run(context: SharedContext) -> io::Result<()> {
-    assert!(
-        context.config().mode.enable_tcp(),
-        "TCP relay must be enabled for TUNNEL"
-    );
+/// How long the accept loop waits for in-flight `handle_tunnel_client` tasks to finish
+/// draining after a shutdown is requested, before aborting them
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
 
-    let local_addr = context.config().local_addr.as_ref().expect("local config");
-    let bind_addr = local_addr.bind_addr(&context).await?;
+/// Waits for all tasks in `tasks` to finish, up to `grace_period`; tasks still running after
+/// that are aborted when `tasks` is dropped on return
+async fn drain_tasks(mut tasks: JoinSet<()>, grace_period: Duration) {
+    if tasks.is_empty() {
+        return;
+    }
 
-    let listener = TcpListener::bind(&bind_addr).await.map_err(|err| {
-        error!("failed to listen on {} ({}), {}", local_addr, bind_addr, err);
-        err
-    })?;
+    info!(
+        "TUNNEL shutting down, waiting up to {:?} for {} in-flight connection(s) to finish",
+        grace_period,
+        tasks.len()
+    );
 
-    let actual_local_addr = listener.local_addr().expect("determine port bound to");
+    let drain = async { while tasks.join_next().await.is_some() {} };
 
-    let servers = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
+    if time::timeout(grace_period, drain).await.is_err() {
+        debug!("TUNNEL shutdown grace period elapsed, aborting remaining connection(s)");
+    }
+}
 
-    let forward_addr = context.config().forward.as_ref().expect("`forward` address in config");
-    info!(
-        "shadowsocks TCP tunnel listening on {}, forward to {}",
-        actual_local_addr, forward_addr
-    );
+async fn run_tcp(
+    listener: TcpListener,
+    servers: PlainPingBalancer,
+    mut shutdown: watch::Receiver<bool>,
+) -> io::Result<()> {
+    let mut tasks = JoinSet::new();
 
     loop {
-        let (socket, peer_addr) = match listener.accept().await {
-            Ok(s) => s,
-            Err(err) => {
-                error!("accept failed with error: {}", err);
-                time::sleep(Duration::from_secs(1)).await;
-                continue;
+        let (socket, peer_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = shutdown.changed() => {
+                debug!("TUNNEL TCP accept loop received shutdown signal");
+                break;
             }
         };
         let server = servers.pick_server();
@@ -135,10 +331,175 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
         trace!("got connection {}", peer_addr);
         trace!("picked proxy server: {:?}", server.server_config());
 
-        tokio::spawn(async move {
-            if let Err(err) = handle_tunnel_client(&server, socket).await {
+        tasks.spawn(async move {
+            if let Err(err) = handle_tunnel_tcp_client(&server, socket).await {
                 debug!("TCP tunnel client exited with error: {:?}", err);
             }
         });
+
+        // Opportunistically reap finished tasks so the set doesn't grow unbounded
+        while tasks.try_join_next().is_some() {}
+    }
+
+    drain_tasks(tasks, SHUTDOWN_GRACE_PERIOD).await;
+    Ok(())
+}
+
+async fn run_unix(
+    listener: UnixListener,
+    servers: PlainPingBalancer,
+    mut shutdown: watch::Receiver<bool>,
+) -> io::Result<()> {
+    let mut tasks = JoinSet::new();
+
+    loop {
+        let (socket, _) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = shutdown.changed() => {
+                debug!("TUNNEL unix accept loop received shutdown signal");
+                break;
+            }
+        };
+        let server = servers.pick_server();
+
+        trace!("got connection on unix socket");
+        trace!("picked proxy server: {:?}", server.server_config());
+
+        tasks.spawn(async move {
+            if let Err(err) = handle_tunnel_client(&server, socket, ClientAddr::Unix).await {
+                debug!("TCP tunnel client exited with error: {:?}", err);
+            }
+        });
+
+        while tasks.try_join_next().is_some() {}
+    }
+
+    drain_tasks(tasks, SHUTDOWN_GRACE_PERIOD).await;
+    Ok(())
+}
+
+/// Runs the tunnel's accept loop until it is cancelled, never stopping on its own
+pub async fn run(context: SharedContext) -> io::Result<()> {
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+    run_until_shutdown(context, shutdown_rx).await
+}
+
+/// Runs the tunnel's accept loop until `shutdown` is signalled (changed to any value, or its
+/// sender dropped), draining in-flight connections for a bounded grace period before returning
+pub async fn run_until_shutdown(
+    context: SharedContext,
+    shutdown: watch::Receiver<bool>,
+) -> io::Result<()> {
+    assert!(
+        context.config().mode.enable_tcp(),
+        "TCP relay must be enabled for TUNNEL"
+    );
+
+    // A dedicated config field drives Unix-socket binding -- there is no way to infer it
+    // from `local_addr`, which only ever represents a host:port
+    let bind_addr = match context.config().tunnel_unix_path {
+        Some(ref path) => BindAddr::Unix(path.clone()),
+        None => {
+            let local_addr = context.config().local_addr.as_ref().expect("local config");
+            BindAddr::Socket(local_addr.bind_addr(&context).await?)
+        }
+    };
+
+    let servers = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
+
+    let forward_addr = context
+        .config()
+        .forward
+        .as_ref()
+        .expect("`forward` address in config");
+
+    match bind_addr {
+        BindAddr::Socket(saddr) => {
+            let listener = TcpListener::bind(saddr).await.map_err(|err| {
+                error!("failed to listen on {}, {}", saddr, err);
+                err
+            })?;
+
+            let actual_local_addr = listener.local_addr().expect("determine port bound to");
+            info!(
+                "shadowsocks TCP tunnel listening on {}, forward to {}",
+                actual_local_addr, forward_addr
+            );
+
+            run_tcp(listener, servers, shutdown).await
+        }
+        BindAddr::Unix(path) => {
+            // Remove a stale socket file left behind by a previous run, mirroring common
+            // Unix daemon behaviour (nginx, systemd socket units) before `bind`.
+            let _ = std::fs::remove_file(&path);
+
+            let listener = UnixListener::bind(&path).map_err(|err| {
+                error!("failed to listen on unix:{}, {}", path.display(), err);
+                err
+            })?;
+
+            info!(
+                "shadowsocks TCP tunnel listening on unix:{}, forward to {}",
+                path.display(),
+                forward_addr
+            );
+
+            run_unix(listener, servers, shutdown).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_tasks_waits_for_inflight_tasks() {
+        let mut tasks = JoinSet::new();
+        tasks.spawn(async {
+            time::sleep(Duration::from_millis(20)).await;
+        });
+
+        let start = std::time::Instant::now();
+        drain_tasks(tasks, Duration::from_secs(5)).await;
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn drain_tasks_gives_up_after_grace_period() {
+        let mut tasks = JoinSet::new();
+        tasks.spawn(async {
+            time::sleep(Duration::from_secs(3600)).await;
+        });
+
+        let start = std::time::Instant::now();
+        drain_tasks(tasks, Duration::from_millis(50)).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn drain_tasks_returns_immediately_when_empty() {
+        let start = std::time::Instant::now();
+        drain_tasks(JoinSet::new(), Duration::from_secs(5)).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn client_addr_unix_has_no_socket_addr() {
+        assert_eq!(ClientAddr::Unix.as_socket_addr(), None);
+    }
+
+    #[test]
+    fn client_addr_socket_round_trips() {
+        let addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        assert_eq!(ClientAddr::Socket(addr).as_socket_addr(), Some(addr));
     }
 }